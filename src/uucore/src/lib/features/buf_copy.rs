@@ -13,9 +13,9 @@ use crate::error::{UError, UResult};
 use nix::unistd;
 use std::fs::File;
 use std::{
-    io::{self, Read, Write},
+    io::{self, BufRead, Read, Write},
     os::{
-        fd::AsFd,
+        fd::{AsFd, BorrowedFd},
         unix::io::{AsRawFd, RawFd},
     },
 };
@@ -73,14 +73,192 @@ where
 const SPLICE_SIZE: usize = 1024 * 128;
 const BUF_SIZE: usize = 1024 * 16;
 
+/// Chunk size used by `copy_file_range_exact` and `sendfile_all` per
+/// syscall. These loop on the fd's actual EOF rather than a byte count
+/// derived from `fstat`, which can't be trusted as an upper bound (e.g.
+/// procfs/sysfs files report `st_size == 0` while still holding real
+/// content), so this is just a throughput tuning knob, not a limit.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const COPY_CHUNK_SIZE: usize = 1024 * 128;
+
+/// Below this many bytes, setting up a temporary pipe and issuing at least
+/// two `splice` syscalls per chunk costs more than a plain `read`/`write`
+/// would, so `Copier` first fills one buffer of this size (or reads to EOF,
+/// whichever comes first) with plain `read` calls, and only falls back
+/// to the pipe-based splice loop once it sees the stream is bigger than
+/// this.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SMALL_COPY_THRESHOLD: usize = 1024 * 8;
+
+/// Classification of a file descriptor, as probed once by `Copier` via
+/// `fstat` and then reused to pick a copy strategy without stat'ing again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+enum FdKind {
+    Pipe,
+    Regular,
+    Other,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl FdKind {
+    /// Classifies an already-fetched `fstat` result, so callers that also
+    /// need other fields from the same `stat` (like `st_size`) don't have to
+    /// call `fstat` a second time.
+    fn classify(stat: &nix::sys::stat::FileStat) -> Self {
+        let mode = stat.st_mode as nix::libc::mode_t;
+        if mode & S_IFIFO != 0 {
+            FdKind::Pipe
+        } else if mode & nix::libc::S_IFMT == nix::libc::S_IFREG {
+            FdKind::Regular
+        } else {
+            FdKind::Other
+        }
+    }
+}
+
+/// Marker trait for handles `Copier` can read from: anything `buf_copy`'s
+/// zero-copy syscalls can operate on needs both `Read` and raw-fd access.
+pub trait CopyRead: Read + AsFd + AsRawFd {}
+impl<T: Read + AsFd + AsRawFd> CopyRead for T {}
+
+/// Marker trait for handles `Copier` can write to. See `CopyRead`.
+pub trait CopyWrite: Write + AsFd + AsRawFd {}
+impl<T: Write + AsFd + AsRawFd> CopyWrite for T {}
+
+/// Dispatches a stream copy to the most efficient syscall available for the
+/// kind of file descriptors involved, probing each side's type with a single
+/// `fstat` and trying candidate strategies in order: `copy_file_range`
+/// (regular file to regular file), `sendfile` (regular file to a non-pipe),
+/// `splice` (pipe on either side), and finally plain `read`/`write` via
+/// `std::io::copy`. A candidate that fails with `EINVAL`, `ENOSYS`, `EBADF`,
+/// or `EXDEV` is treated as "try the next one" rather than a hard error.
+struct Copier;
+
+impl Copier {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn copy<R, S>(src: &mut R, dest: &mut S) -> UResult<u64>
+    where
+        R: CopyRead,
+        S: CopyWrite,
+    {
+        // The strategies below write straight to dest's raw fd, bypassing
+        // any userspace buffer a wrapper like `BufWriter` keeps. Flush it
+        // first so bytes already sitting in that buffer go out ahead of
+        // whatever we're about to splice/send/copy directly.
+        dest.flush()?;
+
+        // One fstat per side, cached in `*_stat` for the rest of this call
+        // so we never stat the same fd twice. Note we only use this to
+        // classify the fd kind, never as a byte-count bound for the copy
+        // below: `st_size` can't be trusted for that (procfs/sysfs files
+        // report `st_size == 0` while still holding real content, and a
+        // regular file can grow between this `fstat` and the copy).
+        let src_stat = fstat(src.as_raw_fd())?;
+        let dest_stat = fstat(dest.as_raw_fd())?;
+        let src_kind = FdKind::classify(&src_stat);
+        let dest_kind = FdKind::classify(&dest_stat);
+
+        // Bytes already moved by a prior candidate that then hit a
+        // recoverable error count towards the total: the fds' offsets have
+        // already advanced by that much, so later candidates only need to
+        // cover what's left.
+        let mut total: u64 = 0;
+
+        // If both ends are regular files, prefer copy_file_range(2): it moves
+        // data entirely in kernel space and can exploit filesystem
+        // reflink/server-side copy, which splice() (needing a pipe on one
+        // end) cannot.
+        if src_kind == FdKind::Regular && dest_kind == FdKind::Regular {
+            let result = copy_file_range_exact(src.as_raw_fd(), dest.as_raw_fd())?;
+            total += result.0;
+            if !result.1 {
+                dest.flush()?;
+                return Ok(total);
+            }
+        }
+
+        // Otherwise, if the source is a regular file and neither end is a
+        // pipe, sendfile(2) is the efficient option: it avoids the
+        // intermediate-pipe round-trip that splice_write() below would need.
+        if src_kind == FdKind::Regular && dest_kind != FdKind::Pipe {
+            let result = sendfile_all(dest.as_raw_fd(), src.as_raw_fd())?;
+            total += result.0;
+            if !result.1 {
+                dest.flush()?;
+                return Ok(total);
+            }
+        }
+
+        // If a pipe is involved on either end, try splice() next — but for
+        // very small transfers the pipe setup and at least two extra
+        // syscalls per chunk cost more than a plain read/write would. Fill a
+        // single bounded buffer first; if that already drains the stream,
+        // skip the pipe entirely.
+        if src_kind == FdKind::Pipe || dest_kind == FdKind::Pipe {
+            let mut small_buf = [0u8; SMALL_COPY_THRESHOLD];
+            let n = fill_or_eof(src, &mut small_buf)?;
+            dest.write_all(&small_buf[..n])?;
+            // splice_write() below writes to dest's raw fd, so these bytes
+            // must leave any userspace buffer of dest before it runs.
+            dest.flush()?;
+            total += n as u64;
+            if n < SMALL_COPY_THRESHOLD {
+                // Hit EOF within the first read: the whole stream was
+                // smaller than the threshold, so we're done.
+                return Ok(total);
+            }
+
+            // The buffer filled up without hitting EOF, so this is a larger
+            // stream after all. Fall into the pipe-based splice loop for the
+            // rest of it; the bytes already read above have already been
+            // written out and flushed, so nothing is lost or reordered.
+            let result = splice_write(src, &dest.as_fd())?;
+            total += result.0;
+            if !result.1 {
+                return Ok(total);
+            }
+            let result = std::io::copy(src, dest)?;
+            total += result;
+            dest.flush()?;
+            return Ok(total);
+        }
+
+        // If none of the above apply, or the chosen syscall failed and is
+        // still recoverable, fall back on slower read/write copying.
+        let result = std::io::copy(src, dest)?;
+        total += result;
+
+        // If a zero-copy call above failed after having written some data to
+        // stdout, and a later call makes a second (successful) attempt, data
+        // pushed through that attempt would be output before the data
+        // buffered in stdout.lock. Therefore an explicit flush is required
+        // here.
+        dest.flush()?;
+        Ok(total)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn copy<R, S>(src: &mut R, dest: &mut S) -> UResult<u64>
+    where
+        R: CopyRead,
+        S: CopyWrite,
+    {
+        let result = std::io::copy(src, dest)?;
+        dest.flush()?;
+        Ok(result)
+    }
+}
+
 /// Copy data from `Read` implementor `source` into a `Write` implementor
 /// `dest`. This works by reading a chunk of data from `source` and writing the
 /// data to `dest` in a loop.
 ///
-/// This function uses the Linux-specific `splice` call when possible which does
-/// not use any intermediate user-space buffer. It falls backs to
-/// `std::io::copy` under other platforms or when the call fails and is still
-/// recoverable.
+/// This is a thin wrapper over `Copier`, which picks the most efficient
+/// Linux syscall available for the kind of file descriptors involved
+/// (`copy_file_range`, `sendfile`, or `splice`) and falls back to
+/// `std::io::copy` under other platforms or when none of those are
+/// supported.
 ///
 /// # Arguments
 /// * `source` - `Read` implementor to copy data from.
@@ -92,29 +270,62 @@ const BUF_SIZE: usize = 1024 * 16;
 /// operation is successful.
 pub fn copy_stream<R, S>(src: &mut R, dest: &mut S) -> UResult<u64>
 where
-    R: Read + AsFd + AsRawFd,
-    S: Write + AsFd + AsRawFd,
+    R: CopyRead,
+    S: CopyWrite,
 {
-    #[cfg(any(target_os = "linux", target_os = "android"))]
-    {
-        // If we're on Linux or Android, try to use the splice() system call
-        // for faster writing. If it works, we're done.
-        let result = splice_write(src, &dest.as_fd())?;
-        if !result.1 {
-            return Ok(result.0);
-        }
-    }
-    // If we're not on Linux or Android, or the splice() call failed,
-    // fall back on slower writing.
-    let result = std::io::copy(src, dest)?;
-
-    // If the splice() call failed and there has been some data written to
-    // stdout via while loop above AND there will be second splice() call
-    // that will succeed, data pushed through splice will be output before
-    // the data buffered in stdout.lock. Therefore additional explicit flush
-    // is required here.
-    dest.flush()?;
-    Ok(result)
+    Copier::copy(src, dest)
+}
+
+/// Writes out the bytes `src` currently has sitting in its internal buffer,
+/// via an ordinary `write`, and consumes them from `src`.
+///
+/// This is a single `fill_buf()`/`consume()` call, not a loop: `fill_buf()`
+/// only returns already-buffered bytes while the buffer is non-empty, but
+/// once it's drained, calling it again triggers a fresh `read()` from the
+/// underlying source. Looping here would read (and buffer-copy) the entire
+/// stream instead of just the prefix that was already sitting in memory,
+/// which defeats the point of falling through to `copy_stream` afterward.
+/// If the buffer is empty on entry, that one `fill_buf()` reads a single
+/// buffer's worth from the underlying source, and those bytes are written
+/// out here as well.
+///
+/// # Returns
+/// The number of bytes drained this way.
+fn drain_buffered_prefix<R, S>(src: &mut R, dest: &mut S) -> io::Result<u64>
+where
+    R: BufRead,
+    S: Write,
+{
+    let buf = src.fill_buf()?;
+    let len = buf.len();
+    dest.write_all(buf)?;
+    src.consume(len);
+    Ok(len as u64)
+}
+
+/// Like `copy_stream`, but for a `source` wrapped in a `BufReader`. The
+/// zero-copy paths `copy_stream` may pick read directly
+/// from `src`'s raw fd, which knows nothing about bytes `src` has already
+/// pulled into its own userspace buffer; skipping this step would silently
+/// drop or reorder that data. So we drain the bytes currently sitting in
+/// `src`'s buffer with an ordinary `write` first, and only then fall through
+/// to `copy_stream` for the rest.
+///
+/// # Arguments
+/// * `source` - buffered `Read` implementor to copy data from.
+/// * `dest` - `Write` implementor to copy data to.
+///
+/// # Returns
+///
+/// Result of operation and bytes successfully written (as a `u64`) when
+/// operation is successful.
+pub fn copy_stream_buffered<R, S>(src: &mut io::BufReader<R>, dest: &mut S) -> UResult<u64>
+where
+    R: CopyRead,
+    S: CopyWrite,
+{
+    let drained = drain_buffered_prefix(src, dest)?;
+    Ok(drained + copy_stream(src.get_mut(), dest)?)
 }
 
 /// Write from source `handle` into destination `write_fd` using Linux-specific
@@ -158,6 +369,114 @@ where
     }
 }
 
+/// Copy the entire remaining content of `src_fd` to `dst_fd` using the
+/// Linux-specific `copy_file_range(2)` system call, which copies entirely
+/// within the kernel and can exploit filesystem-level reflink/server-side
+/// copy. Both file offsets are left untouched by us; the kernel advances its
+/// own internal offsets (we pass `NULL` for both, so it uses and updates the
+/// fds' current file position).
+///
+/// This loops on `COPY_CHUNK_SIZE`-sized requests until the call itself
+/// reports true EOF (a `0` return), rather than stopping once some byte
+/// count derived from `fstat` has been reached: `st_size` isn't a
+/// trustworthy bound here (procfs/sysfs files report `st_size == 0` while
+/// still holding real content, and a regular file can grow between a
+/// preceding `fstat` and this copy), so treating it as one would silently
+/// truncate such copies.
+///
+/// # Returns
+/// The number of bytes actually copied, and a `bool` indicating whether we
+/// have to fall back to another copying method because the call isn't
+/// supported on this filesystem/kernel or for these fds (`EXDEV`, `EINVAL`,
+/// `ENOSYS`, `EBADF`).
+#[inline]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn copy_file_range_exact(src_fd: RawFd, dst_fd: RawFd) -> UResult<(u64, bool)> {
+    let mut copied: u64 = 0;
+    loop {
+        let ret = unsafe {
+            nix::libc::copy_file_range(
+                src_fd,
+                std::ptr::null_mut(),
+                dst_fd,
+                std::ptr::null_mut(),
+                COPY_CHUNK_SIZE,
+                0,
+            )
+        };
+        if ret < 0 {
+            return match Errno::last() {
+                Errno::EXDEV | Errno::EINVAL | Errno::ENOSYS | Errno::EBADF => {
+                    Ok((copied, true))
+                }
+                errno => Err(Error::Io(io::Error::from_raw_os_error(errno as i32)).into()),
+            };
+        }
+        if ret == 0 {
+            // True EOF: the source has no more data to give us.
+            return Ok((copied, false));
+        }
+        copied += ret as u64;
+    }
+}
+
+/// Copy the entire remaining content of regular file `in_fd` to `out_fd`
+/// using the Linux-specific `sendfile(2)` system call, via `nix`'s safe
+/// wrapper. Unlike `splice`, this doesn't require a pipe on either end, so
+/// it's the efficient kernel-space option for copies where the destination
+/// is a socket or another regular file. We pass `None` for the offset, so
+/// the kernel uses and advances `in_fd`'s own file position, the same as
+/// `copy_file_range_exact` above.
+///
+/// This loops on `COPY_CHUNK_SIZE`-sized requests until the call itself
+/// reports true EOF (a `0` return); see `copy_file_range_exact` for why
+/// `fstat`'s `st_size` can't be used as a bound instead.
+///
+/// # Returns
+/// The number of bytes actually written, and a `bool` indicating whether we
+/// have to fall back to another copying method because the call isn't
+/// supported here (`EINVAL`, `ENOSYS`, `EAGAIN`, `EBADF`, `EXDEV`).
+#[inline]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sendfile_all(out_fd: RawFd, in_fd: RawFd) -> UResult<(u64, bool)> {
+    // SAFETY: both fds are borrowed for the lifetime of this call only and
+    // remain owned by the caller.
+    let out_fd = unsafe { BorrowedFd::borrow_raw(out_fd) };
+    let in_fd = unsafe { BorrowedFd::borrow_raw(in_fd) };
+
+    let mut written: u64 = 0;
+    loop {
+        match nix::sys::sendfile::sendfile(out_fd, in_fd, None, COPY_CHUNK_SIZE) {
+            Ok(0) => return Ok((written, false)),
+            Ok(n) => written += n as u64,
+            Err(Errno::EINVAL | Errno::ENOSYS | Errno::EAGAIN | Errno::EBADF | Errno::EXDEV) => {
+                return Ok((written, true))
+            }
+            Err(e) => return Err(Error::from(e).into()),
+        }
+    }
+}
+
+/// Reads from `src` into `buf` until `buf` is full or EOF is reached,
+/// looping over short reads (but not treating them as EOF) along the way.
+///
+/// # Returns
+/// The number of bytes actually read into `buf`. This is less than
+/// `buf.len()` only if EOF was reached first.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fill_or_eof<R: Read>(src: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match src.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 /// Move exactly `num_bytes` bytes from `read_fd` to `write_fd` using the `read`
 /// and `write` calls.
 fn copy_exact(read_fd: RawFd, write_fd: &impl AsFd, num_bytes: usize) -> std::io::Result<usize> {
@@ -334,6 +653,128 @@ mod tests {
         assert_eq!(bytes as usize, data.len());
     }
 
+    #[test]
+    fn test_copy_file_range_exact() {
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("src.txt");
+        let dst_path = temp_dir.path().join("dst.txt");
+        let data = b"Hello, world!";
+        std::fs::write(&src_path, data).unwrap();
+        let src = File::open(&src_path).unwrap();
+        let dst = File::create(&dst_path).unwrap();
+
+        let (bytes, fallback) =
+            copy_file_range_exact(src.as_raw_fd(), dst.as_raw_fd()).unwrap();
+        assert!(!fallback);
+        assert_eq!(bytes as usize, data.len());
+        assert_eq!(std::fs::read(&dst_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_sendfile_all() {
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("src.txt");
+        let dst_path = temp_dir.path().join("dst.txt");
+        let data = b"Hello, world!";
+        std::fs::write(&src_path, data).unwrap();
+        let src = File::open(&src_path).unwrap();
+        let dst = File::create(&dst_path).unwrap();
+
+        let (bytes, fallback) = sendfile_all(dst.as_raw_fd(), src.as_raw_fd()).unwrap();
+        assert!(!fallback);
+        assert_eq!(bytes as usize, data.len());
+        assert_eq!(std::fs::read(&dst_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_copy_file_range_exact_spans_multiple_chunks() {
+        // The loop must keep going on real EOF alone, not on any byte count
+        // derived from fstat (which can't be trusted: e.g. procfs/sysfs
+        // entries report st_size == 0 while still holding real content).
+        // Exercise more than one COPY_CHUNK_SIZE-sized request to prove the
+        // loop doesn't stop early.
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("src.txt");
+        let dst_path = temp_dir.path().join("dst.txt");
+        let data = vec![9u8; COPY_CHUNK_SIZE + 1024];
+        std::fs::write(&src_path, &data).unwrap();
+        let src = File::open(&src_path).unwrap();
+        let dst = File::create(&dst_path).unwrap();
+
+        let (bytes, fallback) = copy_file_range_exact(src.as_raw_fd(), dst.as_raw_fd()).unwrap();
+        assert!(!fallback);
+        assert_eq!(bytes as usize, data.len());
+        assert_eq!(std::fs::read(&dst_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_copy_stream_buffered_drains_bufreader() {
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("src.txt");
+        let dst_path = temp_dir.path().join("dst.txt");
+        let data = b"Hello, buffered world!";
+        std::fs::write(&src_path, data).unwrap();
+
+        let file = File::open(&src_path).unwrap();
+        let mut reader = std::io::BufReader::new(file);
+        // Pull a few bytes through the BufReader so the rest of the file's
+        // contents end up sitting in its internal buffer, already consumed
+        // from the underlying fd.
+        let mut peek = [0u8; 4];
+        reader.read_exact(&mut peek).unwrap();
+        assert_eq!(&peek, &data[..4]);
+
+        let mut dst = File::create(&dst_path).unwrap();
+        let bytes = copy_stream_buffered(&mut reader, &mut dst).unwrap();
+        assert_eq!(bytes as usize, data.len() - 4);
+        assert_eq!(std::fs::read(&dst_path).unwrap(), &data[4..]);
+    }
+
+    #[test]
+    fn test_copy_stream_buffered_falls_through_past_one_buffer_fill() {
+        let temp_dir = tempdir().unwrap();
+        let src_path = temp_dir.path().join("src.bin");
+        let dst_path = temp_dir.path().join("dst.bin");
+        // Much larger than the BufReader's capacity below, so only the
+        // first fill's worth of data should go through drain_buffered_prefix;
+        // the rest must be handed off to copy_stream instead of being read
+        // through the BufReader in a loop.
+        let data = vec![42u8; 64 * 1024];
+        std::fs::write(&src_path, &data).unwrap();
+
+        let file = File::open(&src_path).unwrap();
+        let mut reader = std::io::BufReader::with_capacity(8 * 1024, file);
+        // Trigger exactly one fill, buffering one capacity's worth of bytes
+        // without consuming any of it yet.
+        let filled = reader.fill_buf().unwrap().len();
+        assert_eq!(filled, 8 * 1024);
+
+        let mut dst = File::create(&dst_path).unwrap();
+        let bytes = copy_stream_buffered(&mut reader, &mut dst).unwrap();
+        assert_eq!(bytes as usize, data.len());
+        assert_eq!(std::fs::read(&dst_path).unwrap(), data);
+    }
+
+    #[test]
+    fn test_fill_or_eof_stops_at_eof() {
+        let data = b"Hello, world!";
+        let mut cursor = std::io::Cursor::new(data.to_vec());
+        let mut buf = [0; 1024];
+        let n = fill_or_eof(&mut cursor, &mut buf).unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(&buf[..n], data);
+    }
+
+    #[test]
+    fn test_fill_or_eof_fills_buffer() {
+        let data = vec![7u8; 64];
+        let mut cursor = std::io::Cursor::new(data);
+        let mut buf = [0; 32];
+        let n = fill_or_eof(&mut cursor, &mut buf).unwrap();
+        assert_eq!(n, buf.len());
+        assert!(buf.iter().all(|&b| b == 7));
+    }
+
     #[test]
     fn test_copy_exact() {
         let (mut pipe_read, mut pipe_write) = pipes::pipe().unwrap();